@@ -7,6 +7,7 @@
 //! In memory manifests, used to convert Bonsai Changesets to old style
 
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::io::Write;
 use std::sync::Arc;
 
@@ -225,6 +226,102 @@ impl MemoryManifestEntry {
             _ => false,
         }
     }
+
+    /// Merge two entries coming from the two parents of a merge commit, at the same path.
+    /// Entries that are identical (same blob hash and type, or the same unmodified subtree) are taken
+    /// directly; entries that only exist on one side are taken as-is; trees that differ recurse
+    /// so that only the parts that actually changed get a new entry; anything else (differing
+    /// files, or a file on one side and a tree on the other) becomes a `Conflict` carrying both
+    /// candidates, for bonsai changeset resolution to pick a winner from later.
+    fn merge(p1_entry: Self, p2_entry: Self) -> Self {
+        match (p1_entry, p2_entry) {
+            (MemoryManifestEntry::Blob(p1_blob), MemoryManifestEntry::Blob(p2_blob)) => {
+                // Compare type as well as hash: Mercurial's content hash doesn't encode the
+                // mode/type flag, so a file whose executable bit or symlink-ness differs
+                // between the two parents but whose content is unchanged must still be
+                // treated as a divergence, not silently collapsed to `p1_blob`.
+                if p1_blob.get_hash() == p2_blob.get_hash()
+                    && p1_blob.get_type() == p2_blob.get_type()
+                {
+                    MemoryManifestEntry::Blob(p1_blob)
+                } else {
+                    MemoryManifestEntry::Conflict(vec![
+                        MemoryManifestEntry::Blob(p1_blob),
+                        MemoryManifestEntry::Blob(p2_blob),
+                    ])
+                }
+            }
+            (
+                p1_tree @ MemoryManifestEntry::MemTree { .. },
+                p2_tree @ MemoryManifestEntry::MemTree { .. },
+            ) => {
+                if let (
+                    MemoryManifestEntry::MemTree {
+                        p1: Some(p1_hash),
+                        modified: false,
+                        ..
+                    },
+                    MemoryManifestEntry::MemTree {
+                        p1: Some(p2_hash),
+                        modified: false,
+                        ..
+                    },
+                ) = (&p1_tree, &p2_tree)
+                {
+                    if p1_hash == p2_hash {
+                        // Unmodified subtrees from the same underlying manifest - no need to
+                        // recurse, there's nothing to merge.
+                        return p1_tree;
+                    }
+                }
+                Self::merge_trees(p1_tree, p2_tree)
+            }
+            (p1_entry, p2_entry) => MemoryManifestEntry::Conflict(vec![p1_entry, p2_entry]),
+        }
+    }
+
+    /// Recursively merge two `MemTree`s, child by child, recording both parents' nodehashes on
+    /// the resulting tree so the manifest written out by `save` links back to both histories.
+    fn merge_trees(p1_tree: Self, p2_tree: Self) -> Self {
+        match (p1_tree, p2_tree) {
+            (
+                MemoryManifestEntry::MemTree {
+                    children: mut p1_children,
+                    p1: p1_hash,
+                    ..
+                },
+                MemoryManifestEntry::MemTree {
+                    children: mut p2_children,
+                    p1: p2_hash,
+                    ..
+                },
+            ) => {
+                let names: BTreeSet<MPathElement> = p1_children
+                    .keys()
+                    .chain(p2_children.keys())
+                    .cloned()
+                    .collect();
+
+                let mut children = BTreeMap::new();
+                for name in names {
+                    let merged = match (p1_children.remove(&name), p2_children.remove(&name)) {
+                        (Some(p1_entry), Some(p2_entry)) => Self::merge(p1_entry, p2_entry),
+                        (Some(entry), None) | (None, Some(entry)) => entry,
+                        (None, None) => unreachable!("name came from one of the two children"),
+                    };
+                    children.insert(name, merged);
+                }
+
+                MemoryManifestEntry::MemTree {
+                    children,
+                    p1: p1_hash,
+                    p2: p2_hash,
+                    modified: true,
+                }
+            }
+            _ => unreachable!("merge_trees is only ever called with two MemTree entries"),
+        }
+    }
 }
 
 /// An in memory manifest, created from parent manifests (if any)
@@ -266,20 +363,15 @@ impl MemoryRootManifest {
                     .map(move |root_entry| Self::create(blobstore, root_entry))
                     .boxify()
             }
-            // TODO: This is where the merge case ends up going, when I've worked out
-            // what it looks like. For now, it's all conflicting
             DParents::Two(p1, p2) => {
-                let p1_conflict =
+                let p1_tree =
                     MemoryManifestEntry::convert_treenode(blobstore.clone(), &DManifestId::new(p1));
-                let p2_conflict =
+                let p2_tree =
                     MemoryManifestEntry::convert_treenode(blobstore.clone(), &DManifestId::new(p2));
-                p1_conflict
-                    .join(p2_conflict)
-                    .map(|conflicts| {
-                        Self::create(
-                            blobstore,
-                            MemoryManifestEntry::Conflict(vec![conflicts.0, conflicts.1]),
-                        )
+                p1_tree
+                    .join(p2_tree)
+                    .map(|(p1_tree, p2_tree)| {
+                        Self::create(blobstore, MemoryManifestEntry::merge(p1_tree, p2_tree))
                     })
                     .boxify()
             }
@@ -304,9 +396,33 @@ mod test {
     use super::*;
     use async_unit;
     use many_files_dirs;
-    use mercurial_types::DNodeHash;
+    use mercurial_types::{DNodeHash, FileType};
     use slog::Discard;
 
+    fn make_blob(name: &[u8], hash: &str, ty: Type) -> MemoryManifestEntry {
+        let blobstore = many_files_dirs::getrepo(None).get_blobstore();
+        let nodehash =
+            DNodeHash::from_static_str(hash).expect("Could not get nodehash").into_nodehash();
+        let name = MPathElement::new(name.to_vec()).expect("invalid MPathElement");
+        MemoryManifestEntry::Blob(
+            HgBlobEntry::new(blobstore, Some(name), nodehash, ty)
+                .expect("Could not create blob entry"),
+        )
+    }
+
+    fn empty_tree(p1: &str) -> MemoryManifestEntry {
+        MemoryManifestEntry::MemTree {
+            children: BTreeMap::new(),
+            p1: Some(
+                DNodeHash::from_static_str(p1)
+                    .expect("Could not get nodehash")
+                    .into_mercurial(),
+            ),
+            p2: None,
+            modified: false,
+        }
+    }
+
     fn insert_entry(
         tree: &mut MemoryManifestEntry,
         path: MPathElement,
@@ -440,4 +556,148 @@ mod test {
             );
         })
     }
+
+    #[test]
+    fn merge_identical_blobs_take_p1() {
+        let hash = "b267a6869fcc39b37741408b5823cc044233201d";
+        let p1_blob = make_blob(b"file", hash, Type::File(FileType::Regular));
+        let p2_blob = make_blob(b"file", hash, Type::File(FileType::Regular));
+
+        match MemoryManifestEntry::merge(p1_blob, p2_blob) {
+            MemoryManifestEntry::Blob(blob) => {
+                assert_eq!(
+                    blob.get_hash().into_nodehash(),
+                    DNodeHash::from_static_str(hash)
+                        .expect("Could not get nodehash")
+                        .into_nodehash(),
+                );
+            }
+            _ => panic!("Identical blobs on both sides should merge to a single Blob"),
+        }
+    }
+
+    #[test]
+    fn merge_same_hash_different_type_is_conflict() {
+        let hash = "b267a6869fcc39b37741408b5823cc044233201d";
+        let p1_blob = make_blob(b"file", hash, Type::File(FileType::Regular));
+        let p2_blob = make_blob(b"file", hash, Type::File(FileType::Executable));
+
+        match MemoryManifestEntry::merge(p1_blob, p2_blob) {
+            MemoryManifestEntry::Conflict(sides) => assert_eq!(sides.len(), 2),
+            _ => panic!(
+                "Same content hash but different type (e.g. the executable bit) must still \
+                 conflict"
+            ),
+        }
+    }
+
+    #[test]
+    fn merge_differing_blobs_is_conflict() {
+        let p1_blob = make_blob(
+            b"file",
+            "b267a6869fcc39b37741408b5823cc044233201d",
+            Type::File(FileType::Regular),
+        );
+        let p2_blob = make_blob(
+            b"file",
+            "add0e3d8319d1ef07a4d1921ec4d83dfee01eaff",
+            Type::File(FileType::Regular),
+        );
+
+        match MemoryManifestEntry::merge(p1_blob, p2_blob) {
+            MemoryManifestEntry::Conflict(sides) => assert_eq!(sides.len(), 2),
+            _ => panic!("Differing blobs on both sides should produce a Conflict"),
+        }
+    }
+
+    #[test]
+    fn merge_trees_one_sided_entries_pass_through() {
+        let mut p1_tree = empty_tree("b267a6869fcc39b37741408b5823cc044233201d");
+        let mut p2_tree = empty_tree("add0e3d8319d1ef07a4d1921ec4d83dfee01eaff");
+
+        let only_in_p1 = MPathElement::new(b"only_in_p1".to_vec()).expect("invalid MPathElement");
+        let only_in_p2 = MPathElement::new(b"only_in_p2".to_vec()).expect("invalid MPathElement");
+        insert_entry(
+            &mut p1_tree,
+            only_in_p1.clone(),
+            make_blob(
+                b"only_in_p1",
+                "b267a6869fcc39b37741408b5823cc044233201d",
+                Type::File(FileType::Regular),
+            ),
+        );
+        insert_entry(
+            &mut p2_tree,
+            only_in_p2.clone(),
+            make_blob(
+                b"only_in_p2",
+                "add0e3d8319d1ef07a4d1921ec4d83dfee01eaff",
+                Type::File(FileType::Regular),
+            ),
+        );
+
+        match MemoryManifestEntry::merge(p1_tree, p2_tree) {
+            MemoryManifestEntry::MemTree { children, .. } => {
+                assert!(
+                    children.contains_key(&only_in_p1),
+                    "p1-only entry dropped from merge"
+                );
+                assert!(
+                    children.contains_key(&only_in_p2),
+                    "p2-only entry dropped from merge"
+                );
+                assert_eq!(children.len(), 2);
+            }
+            _ => panic!("Merging two MemTrees should produce a MemTree"),
+        }
+    }
+
+    #[test]
+    fn merge_trees_recurses_into_subtrees() {
+        let mut p1_root = empty_tree("b267a6869fcc39b37741408b5823cc044233201d");
+        let mut p2_root = empty_tree("add0e3d8319d1ef07a4d1921ec4d83dfee01eaff");
+
+        let sub = MPathElement::new(b"sub".to_vec()).expect("invalid MPathElement");
+        let file = MPathElement::new(b"file".to_vec()).expect("invalid MPathElement");
+
+        let mut p1_sub = empty_tree("b267a6869fcc39b37741408b5823cc044233201d");
+        insert_entry(
+            &mut p1_sub,
+            file.clone(),
+            make_blob(
+                b"file",
+                "b267a6869fcc39b37741408b5823cc044233201d",
+                Type::File(FileType::Regular),
+            ),
+        );
+        let mut p2_sub = empty_tree("add0e3d8319d1ef07a4d1921ec4d83dfee01eaff");
+        insert_entry(
+            &mut p2_sub,
+            file.clone(),
+            make_blob(
+                b"file",
+                "add0e3d8319d1ef07a4d1921ec4d83dfee01eaff",
+                Type::File(FileType::Regular),
+            ),
+        );
+
+        insert_entry(&mut p1_root, sub.clone(), p1_sub);
+        insert_entry(&mut p2_root, sub.clone(), p2_sub);
+
+        match MemoryManifestEntry::merge(p1_root, p2_root) {
+            MemoryManifestEntry::MemTree { children, .. } => {
+                match children.get(&sub).expect("sub not present after merge") {
+                    MemoryManifestEntry::MemTree {
+                        children: sub_children,
+                        ..
+                    } => match sub_children.get(&file).expect("file not present after merge") {
+                        MemoryManifestEntry::Conflict(sides) => assert_eq!(sides.len(), 2),
+                        _ => panic!("Differing file content in a subtree should conflict"),
+                    },
+                    _ => panic!("sub should still be a MemTree after recursive merge"),
+                }
+            }
+            _ => panic!("Merging two MemTrees should produce a MemTree"),
+        }
+    }
 }
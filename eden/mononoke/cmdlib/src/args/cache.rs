@@ -5,10 +5,20 @@
  * GNU General Public License version 2.
  */
 
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Error};
 use blobrepo_factory::Caching;
 use clap::{App, Arg, ArgMatches};
 use fbinit::FacebookInit;
 use once_cell::sync::{Lazy, OnceCell};
+use serde::Deserialize;
+use slog::{error, info, warn, Logger};
+use tokio::runtime::Runtime;
 
 use crate::args::MononokeMatches;
 
@@ -19,12 +29,72 @@ const MIN_PROCESS_SIZE: &str = "min-process-size";
 const SKIP_CACHING: &str = "skip-caching";
 const CACHELIB_ONLY_BLOBSTORE: &str = "cachelib-only-blobstore";
 const CACHELIB_SHARDS: &str = "cachelib-shards";
+const CACHELIB_CONFIG: &str = "cachelib-config";
+const CACHE_SIZE_PERCENT: &str = "cache-size-percent";
+const CACHELIB_STATS_INTERVAL: &str = "cachelib-stats-interval";
 
 const PHASES_CACHE_SIZE: &str = "phases-cache-size";
 const BUCKETS_POWER: &str = "buckets-power";
 
 const ONE_GIB: usize = 1073741824; // 2^30 aka 1GiB
 
+/// Memory left unaccounted for when auto-sizing via `--cache-size-percent`, so the cache never
+/// claims the entire host and starves everything else running alongside it.
+const MEMORY_HEADROOM_GIB: u64 = 4;
+
+/// Utilization above which `--cachelib-stats-interval` logs a pool as near-full, rather than an
+/// ordinary info-level snapshot.
+const NEAR_FULL_THRESHOLD_PERCENT: f64 = 90.0;
+
+/// Default `compression_threshold_bytes`: blobs smaller than this rarely compress well enough
+/// to be worth the CPU, so they're stored raw even with compression enabled.
+const DEFAULT_COMPRESSION_THRESHOLD_BYTES: usize = 8 * 1024;
+
+const ZSTD_COMPRESSION_LEVEL: i32 = 3;
+
+/// Upper bound on how large a single decompressed blob is allowed to be, so a corrupt or
+/// maliciously crafted cache entry can't make `decompress_cached_blob` allocate unbounded memory.
+const MAX_DECOMPRESSED_BLOB_BYTES: usize = 512 * 1024 * 1024;
+
+/// Header byte prepended to every blob stored in the cachelib blob pool, so
+/// `decompress_cached_blob` can tell a compressed entry from one stored raw.
+const COMPRESSION_HEADER_RAW: u8 = 0;
+const COMPRESSION_HEADER_ZSTD: u8 = 1;
+
+/// Selects whether blobs written to the cachelib blob pool get zstd-compressed before storage.
+///
+/// Not wired up to any CLI flag or config file field yet: there's no cachelib blob get/put path
+/// in this crate to read this setting from, so it isn't `pub` until there's a caller for it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum BlobCompression {
+    None,
+    Zstd,
+}
+
+impl FromStr for BlobCompression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(BlobCompression::None),
+            "zstd" => Ok(BlobCompression::Zstd),
+            other => bail!("invalid blob compression codec {:?} (expected \"none\" or \"zstd\")", other),
+        }
+    }
+}
+
+/// Names of the individually-sized cachelib pools, as used in the `[pools]` table of a
+/// `--cachelib-config` file and as the basis of each `*-cache-size` flag.
+const POOL_NAMES: &[&str] = &[
+    "blob",
+    "presence",
+    "changesets",
+    "filenodes",
+    "filenodes-history",
+    "idmapping",
+    "phases",
+];
+
 const CACHE_ARGS: &[(&str, &str)] = &[
     ("blob-cache-size", "override size of the blob cache"),
     (
@@ -127,9 +197,157 @@ pub(crate) fn add_cachelib_args<'a, 'b>(
             .takes_value(true)
             .help("number of shards to control concurrent access to a blobstore behind cachelib"),
     )
+    .arg(
+        Arg::with_name(CACHELIB_CONFIG)
+            .long(CACHELIB_CONFIG)
+            .takes_value(true)
+            .value_name("PATH")
+            .help(
+                "path to a TOML file of cachelib settings, applied over the defaults and \
+                 overridden by any of the flags above",
+            ),
+    )
+    .arg(
+        Arg::with_name(CACHE_SIZE_PERCENT)
+            .long(CACHE_SIZE_PERCENT)
+            .takes_value(true)
+            .value_name("PERCENT")
+            .conflicts_with(CACHE_SIZE_GB)
+            .help(
+                "size the cachelib cache as this percentage (0..100) of total host memory, \
+                 clamped between --min-process-size and --max-process-size",
+            ),
+    )
+    .arg(
+        Arg::with_name(CACHELIB_STATS_INTERVAL)
+            .long(CACHELIB_STATS_INTERVAL)
+            .takes_value(true)
+            .value_name("SECONDS")
+            .help(
+                "try to log a per-pool cachelib utilization snapshot at this interval \
+                 (pool stats are not collected in this build, so each attempt logs an error)",
+            ),
+    )
     .args(&cache_args)
 }
 
+/// Parse a human-readable byte size such as `512MiB`, `1.5GiB` or a bare `1073741824` (meaning
+/// bytes). Accepts a case-insensitive 1024-based suffix (`KiB`/`MiB`/`GiB`/`TiB`) or a
+/// 1000-based one (`KB`/`MB`/`GB`/`TB`); a bare `B` suffix is also accepted.
+fn parse_readable_size(input: &str) -> Result<usize, Error> {
+    let trimmed = input.trim();
+    let split_at = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(split_at);
+
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("invalid size {:?}: not a number", input))?;
+    if number < 0.0 {
+        bail!("invalid size {:?}: must not be negative", input);
+    }
+
+    let multiplier: f64 = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1.0,
+        "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        "KIB" => (1u64 << 10) as f64,
+        "MIB" => (1u64 << 20) as f64,
+        "GIB" => (1u64 << 30) as f64,
+        "TIB" => (1u64 << 40) as f64,
+        other => bail!(
+            "invalid size {:?}: unknown unit {:?} (expected B, KB, MB, GB, TB, KiB, MiB, GiB or TiB)",
+            input,
+            other,
+        ),
+    };
+
+    Ok((number * multiplier) as usize)
+}
+
+/// Like [`parse_readable_size`], but for the `*-process-size` flags, whose fields are stored as
+/// whole GiB rather than bytes.
+fn parse_readable_size_gib(input: &str) -> Result<u32, Error> {
+    let bytes = parse_readable_size(input)?;
+    Ok((bytes / ONE_GIB) as u32)
+}
+
+/// Parse `--cache-size-gb`. For back-compat, a bare number here means GiB (unlike every other
+/// size flag, where a bare number means bytes); a unit suffix is still accepted and interpreted
+/// via [`parse_readable_size`].
+fn parse_cache_size_gb(input: &str) -> Result<usize, Error> {
+    let trimmed = input.trim();
+    if trimmed.ends_with(|c: char| c.is_ascii_alphabetic()) {
+        parse_readable_size(trimmed)
+    } else {
+        let gib: f64 = trimmed
+            .parse()
+            .with_context(|| format!("invalid {}: {:?}", CACHE_SIZE_GB, input))?;
+        if gib < 0.0 {
+            bail!("invalid {}: {:?}: must not be negative", CACHE_SIZE_GB, input);
+        }
+        Ok((gib * ONE_GIB as f64) as usize)
+    }
+}
+
+/// Total physical RAM on this host, in bytes.
+fn host_total_memory_bytes() -> Result<u64, Error> {
+    #[cfg(target_os = "linux")]
+    {
+        let meminfo =
+            fs::read_to_string("/proc/meminfo").context("failed to read /proc/meminfo")?;
+        for line in meminfo.lines() {
+            if let Some(kb) = line.strip_prefix("MemTotal:") {
+                let kb: u64 = kb
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("failed to parse /proc/meminfo line {:?}", line))?;
+                return Ok(kb * 1024);
+            }
+        }
+        bail!("MemTotal not found in /proc/meminfo");
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        // TODO: fall back to a sysinfo-style query for non-Linux hosts.
+        bail!("--cache-size-percent is only supported on Linux in this build")
+    }
+}
+
+/// Resolve `--cache-size-percent` into a concrete byte count: `percent` of total host memory,
+/// capped so at least `MEMORY_HEADROOM_GIB` is left for the rest of the system, then clamped
+/// between `settings.min_process_size_gib` and `settings.max_process_size_gib` if they're set.
+fn resolve_cache_size_percent(percent: f64, settings: &CachelibSettings) -> Result<usize, Error> {
+    if !(0.0..=100.0).contains(&percent) {
+        bail!(
+            "{} must be between 0 and 100, got {}",
+            CACHE_SIZE_PERCENT,
+            percent
+        );
+    }
+
+    let total_memory = host_total_memory_bytes()?;
+    let headroom = MEMORY_HEADROOM_GIB.saturating_mul(ONE_GIB as u64);
+    let available = total_memory.saturating_sub(headroom);
+
+    let mut cache_size = ((total_memory as f64) * (percent / 100.0)) as u64;
+    cache_size = cache_size.min(available);
+
+    if let Some(min_gib) = settings.min_process_size_gib {
+        cache_size = cache_size.max((min_gib as u64).saturating_mul(ONE_GIB as u64));
+    }
+    if let Some(max_gib) = settings.max_process_size_gib {
+        cache_size = cache_size.min((max_gib as u64).saturating_mul(ONE_GIB as u64));
+    }
+
+    Ok(cache_size as usize)
+}
+
 pub(crate) fn parse_cachelib_shards<'a>(matches: &ArgMatches<'a>) -> usize {
     match matches.value_of(CACHELIB_SHARDS) {
         Some(v) => v.parse().unwrap(),
@@ -147,13 +365,200 @@ pub(crate) fn parse_caching<'a>(matches: &ArgMatches<'a>) -> Caching {
     }
 }
 
-/// Usual entry point where binary is happy with CachelibSettings::default()
+/// Usual entry point where binary is happy with CachelibSettings::default().
+///
+/// Panics on a malformed `--cachelib-config` file or an invalid flag value: this runs once at
+/// startup, before the server is accepting any work, so there's no sensible way to run with
+/// cachelib left in a half-initialized state, and every caller already treats `init_cachelib`
+/// as infallible.
 pub fn init_cachelib<'a>(fb: FacebookInit, matches: &'a MononokeMatches<'a>) -> Caching {
-    parse_and_init_cachelib(
+    let caching = parse_and_init_cachelib(
         fb,
         matches.as_ref(),
         matches.app_data.cachelib_settings.clone(),
+        matches.logger(),
     )
+    .expect("failed to initialize cachelib");
+
+    if let Some(interval_secs) = matches.value_of(CACHELIB_STATS_INTERVAL) {
+        let interval_secs: u64 = interval_secs
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid {}: {:?}", CACHELIB_STATS_INTERVAL, interval_secs));
+        spawn_cachelib_stats_logger(
+            matches.runtime(),
+            matches.logger().clone(),
+            Duration::from_secs(interval_secs),
+        );
+    }
+
+    caching
+}
+
+/// A snapshot of one cachelib pool's utilization, as returned by [`cachelib_pool_stats`].
+#[derive(Clone, Debug)]
+pub struct PoolStats {
+    pub pool_name: String,
+    pub size_bytes: u64,
+    pub used_bytes: u64,
+    pub item_count: u64,
+    pub hit_rate: f64,
+    pub evictions: u64,
+}
+
+impl PoolStats {
+    pub fn utilization_percent(&self) -> f64 {
+        if self.size_bytes == 0 {
+            0.0
+        } else {
+            (self.used_bytes as f64 / self.size_bytes as f64) * 100.0
+        }
+    }
+}
+
+/// Per-pool utilization snapshot for the pools named in [`POOL_NAMES`].
+///
+/// No cachelib pool stats query exists in this crate: this always returns an error, in every
+/// build, and never a snapshot. Callers (currently just [`log_cachelib_pool_stats`]) log the
+/// error and carry on.
+pub fn cachelib_pool_stats() -> Result<Vec<PoolStats>, Error> {
+    #[cfg(not(fbcode_build))]
+    {
+        bail!("cachelib pool stats are only available for fbcode builds")
+    }
+    #[cfg(fbcode_build)]
+    {
+        bail!(
+            "cachelib pool stats require facebook::cachelib_pool_stats, which doesn't exist yet \
+             in the facebook module"
+        )
+    }
+}
+
+fn log_cachelib_pool_stats(logger: &Logger) {
+    match cachelib_pool_stats() {
+        Ok(stats) => {
+            for pool in stats {
+                let utilization = pool.utilization_percent();
+                if utilization >= NEAR_FULL_THRESHOLD_PERCENT {
+                    warn!(
+                        logger,
+                        "cachelib pool {:?} is {:.1}% full ({}/{} bytes, {} items, {:.1}% hit rate, \
+                         {} evictions) - consider raising its --*-cache-size",
+                        pool.pool_name,
+                        utilization,
+                        pool.used_bytes,
+                        pool.size_bytes,
+                        pool.item_count,
+                        pool.hit_rate * 100.0,
+                        pool.evictions,
+                    );
+                } else {
+                    info!(
+                        logger,
+                        "cachelib pool {:?}: {:.1}% full ({}/{} bytes, {} items, {:.1}% hit rate, \
+                         {} evictions)",
+                        pool.pool_name,
+                        utilization,
+                        pool.used_bytes,
+                        pool.size_bytes,
+                        pool.item_count,
+                        pool.hit_rate * 100.0,
+                        pool.evictions,
+                    );
+                }
+            }
+        }
+        Err(e) => {
+            error!(logger, "failed to collect cachelib pool stats: {:?}", e);
+        }
+    }
+}
+
+/// Spawn a detached background task that logs a [`log_cachelib_pool_stats`] snapshot at `interval`.
+fn spawn_cachelib_stats_logger(runtime: &Runtime, logger: Logger, interval: Duration) {
+    runtime.spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            log_cachelib_pool_stats(&logger);
+        }
+    });
+}
+
+/// A mirror of [`CachelibSettings`] that can be deserialized from a `--cachelib-config` TOML
+/// file. Unknown keys are rejected so a typo in a checked-in profile fails loudly instead of
+/// silently doing nothing.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CachelibSettingsFile {
+    cache_size_gb: Option<f64>,
+    max_process_size_gib: Option<u32>,
+    min_process_size_gib: Option<u32>,
+    buckets_power: Option<u32>,
+    use_tupperware_shrinker: Option<bool>,
+    #[serde(default)]
+    pools: HashMap<String, usize>,
+}
+
+fn load_cachelib_settings_file(path: &Path) -> Result<CachelibSettingsFile, Error> {
+    let raw = fs::read_to_string(path)
+        .with_context(|| format!("failed to read cachelib config file {:?}", path))?;
+    toml::from_str(&raw).with_context(|| format!("failed to parse cachelib config file {:?}", path))
+}
+
+/// Layer a loaded `--cachelib-config` file on top of `settings`, which already holds the
+/// binary's `CachelibSettings::default()`. CLI flags are applied by the caller afterwards, so
+/// they take precedence over anything set here.
+fn apply_cachelib_settings_file(
+    settings: &mut CachelibSettings,
+    file: CachelibSettingsFile,
+) -> Result<(), Error> {
+    if let Some(cache_size_gb) = file.cache_size_gb {
+        if cache_size_gb <= 0.0 {
+            bail!("cachelib config: cache_size_gb must be positive, got {}", cache_size_gb);
+        }
+        settings.cache_size = (cache_size_gb * ONE_GIB as f64) as usize;
+    }
+    if let Some(max_process_size_gib) = file.max_process_size_gib {
+        if max_process_size_gib == 0 {
+            bail!("cachelib config: max_process_size_gib must be positive");
+        }
+        settings.max_process_size_gib = Some(max_process_size_gib);
+    }
+    if let Some(min_process_size_gib) = file.min_process_size_gib {
+        if min_process_size_gib == 0 {
+            bail!("cachelib config: min_process_size_gib must be positive");
+        }
+        settings.min_process_size_gib = Some(min_process_size_gib);
+    }
+    if let Some(buckets_power) = file.buckets_power {
+        settings.buckets_power = Some(buckets_power);
+    }
+    if let Some(use_tupperware_shrinker) = file.use_tupperware_shrinker {
+        settings.use_tupperware_shrinker = use_tupperware_shrinker;
+    }
+
+    for (pool, size) in file.pools {
+        if size == 0 {
+            bail!("cachelib config: pool {:?} size must be positive", pool);
+        }
+        match pool.as_str() {
+            "blob" => settings.blob_cache_size = Some(size),
+            "presence" => settings.presence_cache_size = Some(size),
+            "changesets" => settings.changesets_cache_size = Some(size),
+            "filenodes" => settings.filenodes_cache_size = Some(size),
+            "filenodes-history" => settings.filenodes_history_cache_size = Some(size),
+            "idmapping" => settings.idmapping_cache_size = Some(size),
+            "phases" => settings.phases_cache_size = Some(size),
+            other => bail!(
+                "cachelib config: unknown pool {:?} (expected one of {:?})",
+                other,
+                POOL_NAMES,
+            ),
+        }
+    }
+
+    Ok(())
 }
 
 /// Provide a way for binaries to specify if they have different default cachelib settings
@@ -161,50 +566,110 @@ pub(crate) fn parse_and_init_cachelib<'a>(
     fb: FacebookInit,
     matches: &ArgMatches<'a>,
     mut settings: CachelibSettings,
-) -> Caching {
+    logger: &Logger,
+) -> Result<Caching, Error> {
     let caching = parse_caching(matches);
 
     match caching {
         Caching::Enabled(..) | Caching::CachelibOnlyBlobstore(..) => {
-            if let Some(cache_size) = matches.value_of(CACHE_SIZE_GB) {
-                settings.cache_size =
-                    (cache_size.parse::<f64>().unwrap() * ONE_GIB as f64) as usize;
+            if let Some(config_path) = matches.value_of(CACHELIB_CONFIG) {
+                let file = load_cachelib_settings_file(Path::new(config_path))?;
+                apply_cachelib_settings_file(&mut settings, file)?;
             }
+
             if let Some(max_process_size) = matches.value_of(MAX_PROCESS_SIZE) {
-                settings.max_process_size_gib = Some(max_process_size.parse().unwrap());
+                settings.max_process_size_gib = Some(
+                    parse_readable_size_gib(max_process_size)
+                        .with_context(|| format!("invalid {}: {:?}", MAX_PROCESS_SIZE, max_process_size))?,
+                );
             }
             if let Some(min_process_size) = matches.value_of(MIN_PROCESS_SIZE) {
-                settings.min_process_size_gib = Some(min_process_size.parse().unwrap());
+                settings.min_process_size_gib = Some(
+                    parse_readable_size_gib(min_process_size)
+                        .with_context(|| format!("invalid {}: {:?}", MIN_PROCESS_SIZE, min_process_size))?,
+                );
+            }
+
+            if let Some(cache_size_percent) = matches.value_of(CACHE_SIZE_PERCENT) {
+                let percent: f64 = cache_size_percent.parse().with_context(|| {
+                    format!("invalid {}: {:?}", CACHE_SIZE_PERCENT, cache_size_percent)
+                })?;
+                settings.cache_size = resolve_cache_size_percent(percent, &settings)?;
+                info!(
+                    logger,
+                    "Sized cachelib cache to {} bytes ({:.1}% of host memory)",
+                    settings.cache_size,
+                    percent,
+                );
+            } else if matches.occurrences_of(CACHE_SIZE_GB) > 0 {
+                // `CACHE_SIZE_GB` has a `default_value`, so `value_of` is always `Some(..)`
+                // whether or not the user actually passed the flag; gate on `occurrences_of`
+                // instead so a config-file-supplied `cache_size` isn't clobbered by the
+                // compiled-in default on every run.
+                let cache_size = matches.value_of(CACHE_SIZE_GB).expect("has default_value");
+                settings.cache_size = parse_cache_size_gb(cache_size)?;
+            }
+
+            if matches.is_present(USE_TUPPERWARE_SHRINKER) {
+                settings.use_tupperware_shrinker = true;
             }
-            settings.use_tupperware_shrinker = matches.is_present(USE_TUPPERWARE_SHRINKER);
             if let Some(presence_cache_size) = matches.value_of("presence-cache-size") {
-                settings.presence_cache_size = Some(presence_cache_size.parse().unwrap());
+                settings.presence_cache_size = Some(
+                    parse_readable_size(presence_cache_size)
+                        .with_context(|| format!("invalid presence-cache-size: {:?}", presence_cache_size))?,
+                );
             }
             if let Some(changesets_cache_size) = matches.value_of("changesets-cache-size") {
-                settings.changesets_cache_size = Some(changesets_cache_size.parse().unwrap());
+                settings.changesets_cache_size = Some(
+                    parse_readable_size(changesets_cache_size).with_context(|| {
+                        format!("invalid changesets-cache-size: {:?}", changesets_cache_size)
+                    })?,
+                );
             }
             if let Some(filenodes_cache_size) = matches.value_of("filenodes-cache-size") {
-                settings.filenodes_cache_size = Some(filenodes_cache_size.parse().unwrap());
+                settings.filenodes_cache_size = Some(
+                    parse_readable_size(filenodes_cache_size).with_context(|| {
+                        format!("invalid filenodes-cache-size: {:?}", filenodes_cache_size)
+                    })?,
+                );
             }
             if let Some(filenodes_history_cache_size) =
                 matches.value_of("filenodes-history-cache-size")
             {
                 settings.filenodes_history_cache_size =
-                    Some(filenodes_history_cache_size.parse().unwrap());
+                    Some(parse_readable_size(filenodes_history_cache_size).with_context(|| {
+                        format!(
+                            "invalid filenodes-history-cache-size: {:?}",
+                            filenodes_history_cache_size
+                        )
+                    })?);
             }
             if let Some(idmapping_cache_size) = matches.value_of("idmapping-cache-size") {
-                settings.idmapping_cache_size = Some(idmapping_cache_size.parse().unwrap());
+                settings.idmapping_cache_size = Some(
+                    parse_readable_size(idmapping_cache_size).with_context(|| {
+                        format!("invalid idmapping-cache-size: {:?}", idmapping_cache_size)
+                    })?,
+                );
             }
             if let Some(blob_cache_size) = matches.value_of("blob-cache-size") {
-                settings.blob_cache_size = Some(blob_cache_size.parse().unwrap());
+                settings.blob_cache_size = Some(
+                    parse_readable_size(blob_cache_size)
+                        .with_context(|| format!("invalid blob-cache-size: {:?}", blob_cache_size))?,
+                );
             }
             if let Some(phases_cache_size) = matches.value_of(PHASES_CACHE_SIZE) {
-                settings.phases_cache_size = Some(phases_cache_size.parse().unwrap());
+                settings.phases_cache_size = Some(
+                    parse_readable_size(phases_cache_size)
+                        .with_context(|| format!("invalid {}: {:?}", PHASES_CACHE_SIZE, phases_cache_size))?,
+                );
             }
             if let Some(buckets_power) = matches.value_of(BUCKETS_POWER) {
-                settings.buckets_power = Some(buckets_power.parse().unwrap());
+                settings.buckets_power = Some(
+                    buckets_power
+                        .parse()
+                        .with_context(|| format!("invalid {}: {:?}", BUCKETS_POWER, buckets_power))?,
+                );
             }
-
             #[cfg(not(fbcode_build))]
             {
                 let _ = fb;
@@ -212,7 +677,7 @@ pub(crate) fn parse_and_init_cachelib<'a>(
             }
             #[cfg(fbcode_build)]
             {
-                super::facebook::init_cachelib_from_settings(fb, settings).unwrap();
+                super::facebook::init_cachelib_from_settings(fb, settings)?;
             }
         }
         Caching::Disabled => {
@@ -220,7 +685,7 @@ pub(crate) fn parse_and_init_cachelib<'a>(
         }
     };
 
-    caching
+    Ok(caching)
 }
 
 #[derive(Clone)]
@@ -238,6 +703,8 @@ pub struct CachelibSettings {
     pub blob_cache_size: Option<usize>,
     pub phases_cache_size: Option<usize>,
     pub expected_item_size_bytes: Option<usize>,
+    pub(crate) blob_compression: BlobCompression,
+    pub(crate) compression_threshold_bytes: usize,
 }
 
 impl Default for CachelibSettings {
@@ -256,6 +723,60 @@ impl Default for CachelibSettings {
             blob_cache_size: None,
             phases_cache_size: None,
             expected_item_size_bytes: None,
+            blob_compression: BlobCompression::None,
+            compression_threshold_bytes: DEFAULT_COMPRESSION_THRESHOLD_BYTES,
+        }
+    }
+}
+
+/// Compress `bytes` for storage in the cachelib blob pool per `settings.blob_compression` and
+/// `settings.compression_threshold_bytes`, prepending a header byte so
+/// [`decompress_cached_blob`] can tell a compressed entry from one stored raw. Compression is
+/// skipped (falling back to raw storage) whenever it doesn't actually shrink the blob, so the
+/// header never lies about what follows it. The caller should account `result.len()`, not
+/// `bytes.len()`, toward the pool's occupancy.
+///
+/// Not `pub`: the cachelib blob get/put path itself doesn't exist anywhere in this crate to
+/// call it from, so there's no operator-visible flag or config field wired to
+/// `settings.blob_compression` either. Whatever crate owns that path can call this (and
+/// [`decompress_cached_blob`] on read) once it exists; until then this is crate-internal prep,
+/// not a feature to expose.
+#[allow(dead_code)]
+pub(crate) fn compress_cached_blob(
+    settings: &CachelibSettings,
+    bytes: &[u8],
+) -> Result<Vec<u8>, Error> {
+    if settings.blob_compression == BlobCompression::Zstd
+        && bytes.len() >= settings.compression_threshold_bytes
+    {
+        let compressed = zstd::bulk::compress(bytes, ZSTD_COMPRESSION_LEVEL)
+            .context("failed to zstd-compress blob for cachelib")?;
+        if compressed.len() < bytes.len() {
+            let mut out = Vec::with_capacity(compressed.len() + 1);
+            out.push(COMPRESSION_HEADER_ZSTD);
+            out.extend_from_slice(&compressed);
+            return Ok(out);
         }
     }
+
+    let mut out = Vec::with_capacity(bytes.len() + 1);
+    out.push(COMPRESSION_HEADER_RAW);
+    out.extend_from_slice(bytes);
+    Ok(out)
+}
+
+/// Reverse of [`compress_cached_blob`]: strip the header byte and zstd-decompress the body if
+/// the header says it's compressed. Same caveat as [`compress_cached_blob`]: crate-internal
+/// prep, not yet called from an actual cachelib read path.
+#[allow(dead_code)]
+pub(crate) fn decompress_cached_blob(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let (&header, body) = bytes
+        .split_first()
+        .ok_or_else(|| anyhow!("cached blob is empty, missing compression header byte"))?;
+    match header {
+        COMPRESSION_HEADER_RAW => Ok(body.to_vec()),
+        COMPRESSION_HEADER_ZSTD => zstd::bulk::decompress(body, MAX_DECOMPRESSED_BLOB_BYTES)
+            .context("failed to zstd-decompress cached blob"),
+        other => bail!("cached blob has unknown compression header byte {}", other),
+    }
 }
@@ -159,7 +159,12 @@ pub fn create_runtime(
 
 /// Starts a future as a server, and waits until a termination signal is received.
 ///
-/// When the termination signal is received, the `quiesce` callback is
+/// While running, a SIGHUP causes `reload` (if provided) to be invoked in place: the server
+/// keeps running and accepting requests, and the outcome is logged, rather than entering the
+/// quiesce/shutdown path below. This lets operators re-read things like tunables or log levels
+/// live, without a full restart.
+///
+/// When a termination signal (SIGTERM/SIGINT) is received, the `quiesce` callback is
 /// called.  This should perform any steps required to quiesce the
 /// server.  Requests should still be accepted.
 ///
@@ -170,17 +175,19 @@ pub fn create_runtime(
 ///
 /// Once `shutdown` returns, the `server` future is cancelled, and the process
 /// exits. If `shutdown_timeout` is exceeded, an error is returned.
-pub async fn serve_forever_async<Server, QuiesceFn, ShutdownFut>(
+pub async fn serve_forever_async<Server, QuiesceFn, ReloadFn, ShutdownFut>(
     server: Server,
     logger: &Logger,
     quiesce: QuiesceFn,
     shutdown_grace_period: Duration,
     shutdown: ShutdownFut,
     shutdown_timeout: Duration,
+    mut reload: Option<ReloadFn>,
 ) -> Result<(), Error>
 where
     Server: Future<Output = Result<(), Error>> + Send + 'static,
     QuiesceFn: FnOnce(),
+    ReloadFn: FnMut() -> Result<()>,
     ShutdownFut: Future<Output = ()>,
 {
     // We want to prevent Folly's signal handlers overriding our
@@ -193,8 +200,7 @@ where
 
     let mut terminate = signal(SignalKind::terminate())?;
     let mut interrupt = signal(SignalKind::interrupt())?;
-    // This future becomes ready when we receive a termination signal
-    let signalled = future::select(terminate.next(), interrupt.next());
+    let mut hangup = signal(SignalKind::hangup())?;
 
     let stats_agg = schedule_stats_aggregation_preview()
         .map_err(|_| Error::msg("Failed to create stats aggregation worker"))?;
@@ -204,27 +210,46 @@ where
 
     // Spawn the server onto its own task
     let server_handle = tokio::task::spawn(server);
+    tokio::pin!(server_handle);
+
+    // Wait for the termination signal, or a server exit, reloading in place on every SIGHUP
+    // in the meantime instead of treating it as a shutdown trigger.
+    let server_result: Result<(), Error> = loop {
+        // This future becomes ready when we receive a termination signal
+        let signalled = future::select(terminate.next(), interrupt.next());
 
-    // Now wait for the termination signal, or a server exit.
-    let server_result: Result<(), Error> = match future::select(server_handle, signalled).await {
-        Either::Left((join_handle_res, _)) => {
-            let res = join_handle_res.map_err(Error::from).and_then(|res| res);
-            match res.as_ref() {
-                Ok(()) => {
-                    error!(&logger, "Server has exited! Starting shutdown...");
+        match future::select(&mut server_handle, future::select(signalled, hangup.next())).await {
+            Either::Left((join_handle_res, _)) => {
+                let res = join_handle_res.map_err(Error::from).and_then(|res| res);
+                match res.as_ref() {
+                    Ok(()) => {
+                        error!(&logger, "Server has exited! Starting shutdown...");
+                    }
+                    Err(e) => {
+                        error!(
+                            &logger,
+                            "Server exited with an error! Starting shutdown... Error: {:?}", e
+                        );
+                    }
                 }
-                Err(e) => {
-                    error!(
-                        &logger,
-                        "Server exited with an error! Starting shutdown... Error: {:?}", e
-                    );
+                break res;
+            }
+            Either::Right((Either::Left(..), _)) => {
+                info!(&logger, "Signalled! Starting shutdown...");
+                break Ok(());
+            }
+            Either::Right((Either::Right(..), _)) => {
+                match reload.as_mut() {
+                    Some(reload) => {
+                        info!(&logger, "Received SIGHUP, reloading configuration");
+                        match reload() {
+                            Ok(()) => info!(&logger, "Reloaded configuration"),
+                            Err(e) => error!(&logger, "Failed to reload configuration: {:?}", e),
+                        }
+                    }
+                    None => info!(&logger, "Received SIGHUP, no reload configured; ignoring"),
                 }
             }
-            res
-        }
-        Either::Right(..) => {
-            info!(&logger, "Signalled! Starting shutdown...");
-            Ok(())
         }
     };
 
@@ -247,7 +272,8 @@ where
 }
 
 /// Same as "serve_forever_async", but blocks using the provided runtime,
-/// for compatibility with existing sync code using it.
+/// for compatibility with existing sync code using it. Doesn't reload configuration on
+/// SIGHUP; use `serve_forever_async` directly if you need that.
 pub fn serve_forever<Server, QuiesceFn, ShutdownFut>(
     handle: &Handle,
     server: Server,
@@ -269,6 +295,7 @@ where
         shutdown_grace_period,
         shutdown,
         shutdown_timeout,
+        None::<fn() -> Result<()>>,
     ))
 }
 
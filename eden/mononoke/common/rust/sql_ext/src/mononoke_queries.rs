@@ -6,15 +6,26 @@
  */
 
 use std::future::Future;
+use std::sync::Arc;
 use std::time::Duration;
 
+use anyhow::anyhow;
 use anyhow::Result;
+use once_cell::sync::OnceCell;
 use retry::retry;
 use retry::RetryLogic;
+use sql::Connection;
+use sql::Transaction;
+use sql_query_config::CacheHandler;
+use tokio::sync::Semaphore;
 use tunables::tunables;
 
 const RETRY_ATTEMPTS: usize = 2;
 
+/// Used when no `sql_max_concurrent_queries` tunable is set: effectively unbounded, so the
+/// admission gate is a no-op and today's behavior is preserved.
+const UNBOUNDED_QUERY_PERMITS: usize = 1 << 20;
+
 // This wraps around rust/shed/sql::queries, check that macro: https://fburl.com/code/semq9xm3
 /// Define SQL queries that automatically retry on certain errors.
 #[macro_export]
@@ -88,9 +99,25 @@ macro_rules! mononoke_queries {
                     $( $pname: & $ptype, )*
                     $( $lname: & [ $ltype ], )*
                 ) -> Result<Vec<($( $rtype, )*)>> {
-                    query_with_retry(
-                        || [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*),
-                    ).await
+                    match connection {
+                        Connection::Sqlite(_) => {
+                            let connection = connection.clone();
+                            $( let $pname = $pname.clone(); )*
+                            $( let $lname = $lname.to_vec(); )*
+                            $crate::run_blocking(move || {
+                                futures::executor::block_on(query_with_retry(
+                                    false,
+                                    || [<$name Impl>]::query(&connection, $( &$pname, )* $( &$lname, )*),
+                                ))
+                            }).await
+                        }
+                        _ => {
+                            query_with_retry(
+                                false,
+                                || [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*),
+                            ).await
+                        }
+                    }
                 }
             }
 
@@ -128,14 +155,50 @@ macro_rules! mononoke_queries {
 
                 #[allow(dead_code)]
                 pub async fn query(
-                    _config: &SqlQueryConfig,
+                    config: &SqlQueryConfig,
                     connection: &Connection,
                     $( $pname: & $ptype, )*
                     $( $lname: & [ $ltype ], )*
-                ) -> Result<Vec<($( $rtype, )*)>> {
-                    query_with_retry(
-                        || [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*),
-                    ).await
+                ) -> Result<Vec<($( $rtype, )*)>>
+                where
+                    $( $ptype: serde::Serialize, )*
+                    $( $ltype: serde::Serialize, )*
+                    $( $rtype: serde::Serialize + serde::de::DeserializeOwned, )*
+                {
+                    let run_uncached = || async {
+                        match connection {
+                            Connection::Sqlite(_) => {
+                                let connection = connection.clone();
+                                $( let $pname = $pname.clone(); )*
+                                $( let $lname = $lname.to_vec(); )*
+                                $crate::run_blocking(move || {
+                                    futures::executor::block_on(query_with_retry(
+                                        false,
+                                        || [<$name Impl>]::query(&connection, $( &$pname, )* $( &$lname, )*),
+                                    ))
+                                }).await
+                            }
+                            _ => {
+                                query_with_retry(
+                                    false,
+                                    || [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*),
+                                ).await
+                            }
+                        }
+                    };
+
+                    match config.cache_handler() {
+                        Some(cache) => {
+                            let key = $crate::sql_query_cache_key(
+                                stringify!($name),
+                                &( $( $pname, )* ),
+                                &( $( $lname, )* ),
+                            )?;
+                            $crate::cached_query(&cache, &key, run_uncached()).await
+                        }
+                        // No caching backend configured: today's uncached behavior.
+                        None => run_uncached().await,
+                    }
                 }
             }
 
@@ -194,9 +257,32 @@ macro_rules! mononoke_queries {
                     values: &[($( & $vtype, )*)],
                     $( $pname: & $ptype ),*
                 ) -> Result<WriteResult> {
-                    query_with_retry(
-                        || [<$name Impl>]::query(connection, values $( , $pname )* ),
-                    ).await
+                    match connection {
+                        Connection::Sqlite(_) => {
+                            let connection = connection.clone();
+                            let values: Vec<($( $vtype, )*)> = values
+                                .iter()
+                                .map(|($( $vname, )*)| ($( $vname.clone(), )*))
+                                .collect();
+                            $( let $pname = $pname.clone(); )*
+                            $crate::run_blocking(move || {
+                                let values: Vec<($( &$vtype, )*)> = values
+                                    .iter()
+                                    .map(|($( $vname, )*)| ($( $vname, )*))
+                                    .collect();
+                                futures::executor::block_on(query_with_retry(
+                                    true,
+                                    || [<$name Impl>]::query(&connection, &values, $( &$pname, )* ),
+                                ))
+                            }).await
+                        }
+                        _ => {
+                            query_with_retry(
+                                true,
+                                || [<$name Impl>]::query(connection, values $( , $pname )* ),
+                            ).await
+                        }
+                    }
                 }
             }
 
@@ -255,9 +341,134 @@ macro_rules! mononoke_queries {
                     $( $pname: & $ptype, )*
                     $( $lname: & [ $ltype ], )*
                 ) -> Result<WriteResult> {
-                    query_with_retry(
-                        || [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*),
-                    ).await
+                    match connection {
+                        Connection::Sqlite(_) => {
+                            let connection = connection.clone();
+                            $( let $pname = $pname.clone(); )*
+                            $( let $lname = $lname.to_vec(); )*
+                            $crate::run_blocking(move || {
+                                futures::executor::block_on(query_with_retry(
+                                    true,
+                                    || [<$name Impl>]::query(&connection, $( &$pname, )* $( &$lname, )*),
+                                ))
+                            }).await
+                        }
+                        _ => {
+                            query_with_retry(
+                                true,
+                                || [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*),
+                            ).await
+                        }
+                    }
+                }
+            }
+
+            $crate::mononoke_queries! { $( $rest )* }
+        }
+    };
+
+    // Retryable write query with a single expression. Redirect to retryable write query with
+    // same expression for mysql and sqlite.
+    (
+        $vi:vis write $name:ident (
+            $( $pname:ident: $ptype:ty ),* $(,)*
+            $( >list $lname:ident: $ltype:ty )*
+        ) { $qtype:ident, retryable, $q:expr }
+        $( $rest:tt )*
+    ) => {
+        $crate::mononoke_queries! {
+            $vi write $name (
+                $( $pname: $ptype, )*
+                $( >list $lname: $ltype )*
+            ) { $qtype, retryable, mysql($q) sqlite($q) }
+            $( $rest )*
+        }
+    };
+
+    // Full retryable write query. Call `sql::queries!` and re-export stuff, wrapped in
+    // retries, on a new module. In addition to the usual `query` function, this also emits
+    // `query_in_transaction_with_retry`, which opens its own transaction and retries the whole
+    // thing from scratch on a deadlock (1213) or lock-wait-timeout (1205).
+    (
+        $vi:vis write $name:ident (
+            $( $pname:ident: $ptype:ty ),* $(,)*
+            $( >list $lname:ident: $ltype:ty )*
+        ) { $qtype:ident, retryable, mysql($mysql_q:expr) sqlite($sqlite_q:expr) }
+        $( $rest:tt )*
+    ) => {
+        $crate::_macro_internal::paste::item! {
+            $crate::_macro_internal::queries! {
+                pub write [<$name Impl>] (
+                    $( $pname: $ptype, )*
+                    $( >list $lname: $ltype )*
+                ) { $qtype, mysql($mysql_q) sqlite($sqlite_q) }
+            }
+
+            #[allow(non_snake_case)]
+            $vi mod $name {
+                #[allow(unused_imports)]
+                use super::*;
+
+                #[allow(unused_imports)]
+                use $crate::_macro_internal::*;
+
+                #[allow(unused_imports)]
+                pub use [<$name Impl>]::query_with_transaction;
+
+                #[allow(dead_code)]
+                pub async fn query(
+                    connection: &Connection,
+                    $( $pname: & $ptype, )*
+                    $( $lname: & [ $ltype ], )*
+                ) -> Result<WriteResult> {
+                    match connection {
+                        Connection::Sqlite(_) => {
+                            let connection = connection.clone();
+                            $( let $pname = $pname.clone(); )*
+                            $( let $lname = $lname.to_vec(); )*
+                            $crate::run_blocking(move || {
+                                futures::executor::block_on(query_with_retry(
+                                    true,
+                                    || [<$name Impl>]::query(&connection, $( &$pname, )* $( &$lname, )*),
+                                ))
+                            }).await
+                        }
+                        _ => {
+                            query_with_retry(
+                                true,
+                                || [<$name Impl>]::query(connection, $( $pname, )* $( $lname, )*),
+                            ).await
+                        }
+                    }
+                }
+
+                /// Like `query`, but runs inside a transaction that is rolled back and
+                /// re-opened from scratch if a deadlock or lock-wait-timeout is hit. The
+                /// transaction is driven to completion (including commit) by this function,
+                /// so the caller never sees the `Transaction` handle.
+                #[allow(dead_code)]
+                pub async fn query_in_transaction_with_retry(
+                    connection: &Connection,
+                    $( $pname: & $ptype, )*
+                    $( $lname: & [ $ltype ], )*
+                ) -> Result<WriteResult> {
+                    match connection {
+                        Connection::Sqlite(_) => {
+                            let connection = connection.clone();
+                            $( let $pname = $pname.clone(); )*
+                            $( let $lname = $lname.to_vec(); )*
+                            $crate::run_blocking(move || {
+                                futures::executor::block_on($crate::transaction_with_retry(&connection, |txn| {
+                                    [<$name Impl>]::query_with_transaction(txn, $( &$pname, )* $( &$lname, )*)
+                                }))
+                            }).await
+                        }
+                        _ => {
+                            $crate::transaction_with_retry(connection, |txn| {
+                                [<$name Impl>]::query_with_transaction(txn, $( $pname, )* $( $lname, )*)
+                            }).await
+                        }
+                    }
                 }
             }
 
@@ -268,56 +479,303 @@ macro_rules! mononoke_queries {
 }
 
 #[cfg(fbcode_build)]
-/// See https://fburl.com/sv/uk8w71td for error descriptions
-fn retryable_mysql_errno(errno: u32) -> bool {
-    match errno {
-        // Admission control errors
-        // Safe to retry on writes as well as the query didn't even start
-        1914..=1916 => true,
+/// Admission-control errors: the query never even started, so these are always safe to retry,
+/// on reads and writes alike. See https://fburl.com/sv/uk8w71td for error descriptions.
+fn retryable_admission_control_errno(errno: u32) -> bool {
+    matches!(errno, 1914..=1916)
+}
+
+#[cfg(fbcode_build)]
+/// Transient connection failures: the server went away (2006) or the connection was lost
+/// mid-query (2013). These are safe to retry for reads (which have no side effects), and for
+/// writes only when the error is a `ConnectionOperationError`, i.e. the write provably never
+/// reached the server. See https://fburl.com/sv/uk8w71td for error descriptions.
+fn retryable_connection_errno(errno: u32) -> bool {
+    matches!(errno, 2006 | 2013)
+}
+
+#[cfg(fbcode_build)]
+fn should_retry_mysql_query(is_write: bool, err: &anyhow::Error) -> bool {
+    use mysql_client::MysqlError;
+    use MysqlError::*;
+    match err.downcast_ref::<MysqlError>() {
+        // The query never reached the server, so it's always safe to retry.
+        Some(ConnectionOperationError { mysql_errno, .. }) => {
+            let errno = *mysql_errno;
+            retryable_admission_control_errno(errno) || retryable_connection_errno(errno)
+        }
+        // The query reached the server and got at least partway through executing. For a
+        // write, we can no longer tell whether its effects landed before the error occurred,
+        // so only admission-control errors (by definition never started) are safe to retry.
+        Some(QueryResultError { mysql_errno, .. }) => {
+            let errno = *mysql_errno;
+            retryable_admission_control_errno(errno)
+                || (!is_write && retryable_connection_errno(errno))
+        }
         _ => false,
     }
 }
 
+#[cfg(not(fbcode_build))]
+fn should_retry_mysql_query(_is_write: bool, _err: &anyhow::Error) -> bool {
+    false
+}
+
+#[cfg(fbcode_build)]
+/// Errors that are safe to retry a *whole transaction* on, because the
+/// transaction is rolled back and re-run from scratch: a deadlock (1213) or
+/// a lock-wait timeout (1205) mean none of the transaction's writes took
+/// effect, so replaying it is always safe as long as the caller's closure is
+/// idempotent with respect to in-memory state.
+fn retryable_transaction_mysql_errno(errno: u32) -> bool {
+    match errno {
+        1213 | 1205 => true,
+        errno => retryable_admission_control_errno(errno) || retryable_connection_errno(errno),
+    }
+}
+
 #[cfg(fbcode_build)]
-fn should_retry_mysql_query(err: &anyhow::Error) -> bool {
+fn should_retry_transaction(err: &anyhow::Error) -> bool {
     use mysql_client::MysqlError;
     use MysqlError::*;
     match err.downcast_ref::<MysqlError>() {
         Some(ConnectionOperationError { mysql_errno, .. })
-        | Some(QueryResultError { mysql_errno, .. }) => retryable_mysql_errno(*mysql_errno),
+        | Some(QueryResultError { mysql_errno, .. }) => {
+            retryable_transaction_mysql_errno(*mysql_errno)
+        }
         _ => false,
     }
 }
 
 #[cfg(not(fbcode_build))]
-fn should_retry_mysql_query(err: &anyhow::Error) -> bool {
+fn should_retry_transaction(_err: &anyhow::Error) -> bool {
     false
 }
 
-pub async fn query_with_retry<T, Fut>(mut do_query: impl FnMut() -> Fut + Send) -> Result<T>
+/// Run `job` on the Tokio blocking-pool instead of the current worker thread, for
+/// synchronous, CPU/IO-bound work (such as a query against an embedded SQLite engine) that
+/// would otherwise stall the executor for its duration. Panics inside `job` are re-raised via
+/// `resume_unwind` rather than being swallowed into a cancelled `JoinHandle`.
+#[doc(hidden)]
+pub async fn run_blocking<F, R>(job: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    match tokio::task::spawn_blocking(job).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// How long a `cacheable read` entry stays in the read-through cache before it must be
+/// refreshed from the database, when `sql_cacheable_read_ttl_ms` is unset.
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Read the cache TTL for `cacheable read` queries from `tunables`, so operators can tune it
+/// per-deployment the same way the retry policy and admission semaphore are tuned, falling
+/// back to [`DEFAULT_CACHE_TTL`] when unset.
+///
+/// Like the other `sql_ext` getters added alongside this one, `get_sql_cacheable_read_ttl_ms`
+/// only resolves if `sql_cacheable_read_ttl_ms` is declared in the tunables schema this binary
+/// is built against; that schema lives outside this crate and must land alongside this change.
+fn cache_ttl() -> Duration {
+    tunables()
+        .get_sql_cacheable_read_ttl_ms()
+        .and_then(|v| u64::try_from(v).ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_CACHE_TTL)
+}
+
+/// Build a stable cache key for a `cacheable read` query from its name and bind parameters, so
+/// that two calls with the same parameters hit the same cache entry.
+#[doc(hidden)]
+pub fn sql_query_cache_key(
+    query_name: &str,
+    params: &impl serde::Serialize,
+    list_params: &impl serde::Serialize,
+) -> Result<String> {
+    use std::hash::Hash;
+    use std::hash::Hasher;
+
+    let mut bytes = bincode::serialize(params)?;
+    bytes.extend(bincode::serialize(list_params)?);
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    Ok(format!("{}.{:x}", query_name, hasher.finish()))
+}
+
+/// Run `do_query` through `cache`'s read-through cache keyed on `key`: serve a decodable hit
+/// without touching the database, and on a miss (or an entry that fails to decode, e.g. after
+/// a schema change) fall back to `do_query` and populate the cache for next time.
+#[doc(hidden)]
+pub async fn cached_query<T, Fut>(cache: &CacheHandler, key: &str, do_query: Fut) -> Result<T>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    Fut: Future<Output = Result<T>>,
+{
+    if let Some(bytes) = cache.get(key).await? {
+        if let Ok(value) = bincode::deserialize::<T>(&bytes) {
+            return Ok(value);
+        }
+    }
+
+    let value = do_query.await?;
+    cache
+        .set(key, bincode::serialize(&value)?, cache_ttl())
+        .await?;
+    Ok(value)
+}
+
+/// Read the retry policy (attempt count and exponential backoff parameters) from `tunables`,
+/// so operators can tune it per-deployment the same way other config-driven services expose
+/// e.g. `min_conn`/`max_conn`, falling back to the historical hard-coded values when unset.
+///
+/// `get_sql_retry_attempts`/`get_sql_retry_base_ms`/`get_sql_retry_jitter_ms` assume entries
+/// named `sql_retry_attempts`, `sql_retry_base_ms` and `sql_retry_jitter_ms` exist in the
+/// tunables schema this binary is built against; that schema lives outside this crate (and
+/// outside this change), so it must be updated alongside this landing for these getters to
+/// resolve to anything other than a compile error.
+fn retry_policy() -> (usize, RetryLogic) {
+    let attempts = tunables()
+        .get_sql_retry_attempts()
+        .and_then(|v| usize::try_from(v).ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(RETRY_ATTEMPTS);
+    let base = tunables()
+        .get_sql_retry_base_ms()
+        .and_then(|v| u64::try_from(v).ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_secs(10));
+    let jitter = tunables()
+        .get_sql_retry_jitter_ms()
+        .and_then(|v| u64::try_from(v).ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_secs(5));
+    (
+        attempts,
+        // See https://fburl.com/7dmedu1u for backoff reasoning
+        RetryLogic::ExponentialWithJitter {
+            base,
+            factor: 1.2,
+            jitter,
+        },
+    )
+}
+
+/// Bounds how many queries this process will have in flight (across admission and whatever
+/// retries they go through) at once, so a caller waiting behind an already-overloaded MySQL
+/// tier backs off instead of piling more concurrent work onto it. Sized the first time it's
+/// needed from `sql_max_concurrent_queries`; like the cachelib pool sizes this is a
+/// process-startup setting, not one that can shrink a live semaphore underneath callers
+/// already holding permits.
+///
+/// Like [`retry_policy`]'s getters, `get_sql_max_concurrent_queries` (used here) and
+/// `get_sql_query_admission_timeout_ms` (used by [`query_admission_timeout`] below) require
+/// `sql_max_concurrent_queries` and `sql_query_admission_timeout_ms` entries in the tunables
+/// schema, which lives outside this crate and must land alongside this change.
+static QUERY_ADMISSION_SEMAPHORE: OnceCell<Arc<Semaphore>> = OnceCell::new();
+
+fn query_admission_semaphore() -> Arc<Semaphore> {
+    QUERY_ADMISSION_SEMAPHORE
+        .get_or_init(|| {
+            let permits = tunables()
+                .get_sql_max_concurrent_queries()
+                .and_then(|v| usize::try_from(v).ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(UNBOUNDED_QUERY_PERMITS);
+            Arc::new(Semaphore::new(permits))
+        })
+        .clone()
+}
+
+fn query_admission_timeout() -> Duration {
+    tunables()
+        .get_sql_query_admission_timeout_ms()
+        .and_then(|v| u64::try_from(v).ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::MAX)
+}
+
+pub async fn query_with_retry<T, Fut>(
+    is_write: bool,
+    mut do_query: impl FnMut() -> Fut + Send,
+) -> Result<T>
 where
     T: Send + 'static,
     Fut: Future<Output = Result<T>>,
 {
+    // Held for the whole call, including any retries below, so a query that keeps getting
+    // retried continues to count against the same admission budget rather than freeing up a
+    // slot for more concurrent work to pile in.
+    let _permit = tokio::time::timeout(
+        query_admission_timeout(),
+        query_admission_semaphore().acquire_owned(),
+    )
+    .await
+    .map_err(|_| anyhow!("query admission timed out"))?
+    .expect("query admission semaphore is never closed");
+
     if tunables().get_disable_sql_auto_retries() {
         return do_query().await;
     }
+    let (attempts, backoff) = retry_policy();
     Ok(retry(
         None,
         |_| do_query(),
-        should_retry_mysql_query,
-        // See https://fburl.com/7dmedu1u for backoff reasoning
-        RetryLogic::ExponentialWithJitter {
-            base: Duration::from_secs(10),
-            factor: 1.2,
-            jitter: Duration::from_secs(5),
-        },
-        RETRY_ATTEMPTS,
+        move |err| should_retry_mysql_query(is_write, err),
+        backoff,
+        attempts,
     )
     .await?
     .0)
 }
 
+/// Run `do_transaction` inside a freshly opened `Transaction`, committing it on success. If
+/// the transaction fails with a deadlock or lock-wait timeout, it is rolled back (by dropping
+/// it without committing) and `do_transaction` is invoked again from a brand new transaction,
+/// under the same backoff used by `query_with_retry`.
+///
+/// Because the whole transaction may run more than once, `do_transaction` must be idempotent
+/// with respect to any in-memory state it closes over: only its effect on the `Transaction` it
+/// is handed is re-done atomically, anything mutated outside of that is not undone between
+/// attempts.
+pub async fn transaction_with_retry<T, Fut>(
+    connection: &Connection,
+    mut do_transaction: impl FnMut(Transaction) -> Fut + Send,
+) -> Result<T>
+where
+    T: Send + 'static,
+    Fut: Future<Output = Result<(Transaction, T)>>,
+{
+    // Held for the whole call, including any retries below, for the same reason
+    // `query_with_retry` holds it: a transaction that keeps getting retried should continue to
+    // count against the same admission budget rather than freeing up a slot for more concurrent
+    // work to pile in.
+    let _permit = tokio::time::timeout(
+        query_admission_timeout(),
+        query_admission_semaphore().acquire_owned(),
+    )
+    .await
+    .map_err(|_| anyhow!("query admission timed out"))?
+    .expect("query admission semaphore is never closed");
+
+    let attempt = || async {
+        let txn = connection.start_transaction().await?;
+        let (txn, res) = do_transaction(txn).await?;
+        txn.commit().await?;
+        Ok(res)
+    };
+
+    if tunables().get_disable_sql_auto_retries() {
+        return attempt().await;
+    }
+    let (attempts, backoff) = retry_policy();
+    Ok(retry(None, |_| attempt(), should_retry_transaction, backoff, attempts)
+        .await?
+        .0)
+}
+
 #[cfg(test)]
 mod tests {
     mononoke_queries! {
@@ -338,6 +796,10 @@ mod tests {
             mysql("DELETE FROM my_table where id = {id}")
             sqlite("DELETE FROM mytable2 where id = {id}")
         }
+        write TestQuery5(id: &str) {
+            none, retryable,
+            "DELETE FROM my_table where id = {id}"
+        }
     }
 
     #[allow(dead_code, unreachable_code, unused_variables)]
@@ -353,6 +815,8 @@ mod tests {
         TestQuery3::query(connection, &[(&12,)]).await?;
         TestQuery3::query_with_transaction(todo!(), &[(&12,)]).await?;
         TestQuery4::query(connection, &"hello").await?;
+        TestQuery5::query(connection, &"hello").await?;
+        TestQuery5::query_in_transaction_with_retry(connection, &"hello").await?;
         Ok(())
     }
 }
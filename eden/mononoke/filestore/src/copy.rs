@@ -5,15 +5,22 @@
  * GNU General Public License version 2.
  */
 
+use anyhow::bail;
 use anyhow::Result;
 use blobstore::BlobCopier;
 use blobstore::Blobstore;
+use blobstore::BlobstoreIsPresent;
 use blobstore::Loadable;
 use context::CoreContext;
 use futures::future;
 use futures::stream;
+use futures::stream::Stream;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
+use mononoke_types::hash::Blake3;
+use mononoke_types::hash::GitSha1;
+use mononoke_types::hash::Sha1;
+use mononoke_types::hash::Sha256;
 use mononoke_types::BlobstoreKey;
 use mononoke_types::BlobstoreValue;
 use mononoke_types::ContentMetadataV2;
@@ -23,49 +30,342 @@ use crate::Alias;
 use crate::FileContents;
 use crate::FilestoreConfig;
 
+/// The 32-byte key `seeded_blake3` is hashed with; must match whatever the filestore uses when
+/// it first computes `ContentMetadataV2::seeded_blake3` so that a verified copy recomputes the
+/// exact same digest.
+const SEEDED_BLAKE3_KEY: &[u8; 32] = b"20220728-2357111317192329313741#";
+
+/// Controls how thoroughly [`copy`] checks the data it just copied.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CopyMode {
+    /// Copy the blobs and trust that what landed at the destination matches the source.
+    Fast,
+    /// After copying, reload the destination content (reassembling chunks if needed),
+    /// recompute every alias hash plus the total size, and fail if any of them disagree with
+    /// what `ContentMetadataV2` recorded for the source.
+    Verified,
+}
+
+/// Controls whether [`copy`] trusts a key that's already present at the destination, making an
+/// interrupted bulk copy cheap and idempotent to resume.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResumeMode {
+    /// Copy every key unconditionally, even ones that already exist at the destination.
+    Full,
+    /// Probe the destination for each key first, and elide the copy if it's already there.
+    Resume,
+}
+
+/// Tallies, for one call to [`copy`], how many of its blobstore keys (aliases, chunks, the
+/// content blob and the metadata blob) were actually copied versus found already present at the
+/// destination and left alone.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CopyOutcome {
+    pub copied_keys: usize,
+    pub skipped_keys: usize,
+}
+
+impl CopyOutcome {
+    fn single(copied: bool) -> Self {
+        if copied {
+            CopyOutcome {
+                copied_keys: 1,
+                skipped_keys: 0,
+            }
+        } else {
+            CopyOutcome {
+                copied_keys: 0,
+                skipped_keys: 1,
+            }
+        }
+    }
+
+    fn add(&mut self, other: Self) {
+        self.copied_keys += other.copied_keys;
+        self.skipped_keys += other.skipped_keys;
+    }
+}
+
+/// Copy a single blobstore key, skipping the actual transfer if `resume` is [`ResumeMode::Resume`]
+/// and the key is already present at the destination.
+async fn copy_key(
+    destination_blobstore: &impl Blobstore,
+    copier: &impl BlobCopier,
+    ctx: &CoreContext,
+    resume: ResumeMode,
+    key: String,
+) -> Result<CopyOutcome> {
+    if resume == ResumeMode::Resume {
+        if let BlobstoreIsPresent::Present = destination_blobstore.is_present(ctx, &key).await? {
+            return Ok(CopyOutcome::single(false));
+        }
+    }
+
+    copier.copy(ctx, key).await?;
+    Ok(CopyOutcome::single(true))
+}
+
 pub async fn copy(
     original_blobstore: &impl Blobstore,
     copier: &impl BlobCopier,
     config: FilestoreConfig,
     ctx: &CoreContext,
     data: &ContentMetadataV2,
-) -> Result<()> {
+    mode: CopyMode,
+    // Read from in `CopyMode::Verified`, to reload what `copier` just wrote and confirm it
+    // hashes to what `data` claims, and in `ResumeMode::Resume`, to probe for already-copied
+    // keys. Otherwise unused.
+    destination_blobstore: &impl Blobstore,
+    resume: ResumeMode,
+) -> Result<CopyOutcome> {
     // See reasoning about order of writes in ./finalize.rs::finalize (https://fburl.com/code/3w8dncr3)
 
+    let mut outcome = CopyOutcome::default();
+
     // Ensure that all aliases are covered, and missing out an alias gives a compile time error.
-    future::try_join_all(Alias::iter().map(|alias| match alias {
-        Alias::Sha1(_) => copier.copy(ctx, Alias::Sha1(data.sha1).blobstore_key()),
-        Alias::GitSha1(_) => copier.copy(ctx, Alias::GitSha1(data.git_sha1.sha1()).blobstore_key()),
-        Alias::Sha256(_) => copier.copy(ctx, Alias::Sha256(data.sha256).blobstore_key()),
-        Alias::SeededBlake3(_) => {
-            copier.copy(ctx, Alias::SeededBlake3(data.seeded_blake3).blobstore_key())
-        }
+    for alias_outcome in future::try_join_all(Alias::iter().map(|alias| {
+        let key = match alias {
+            Alias::Sha1(_) => Alias::Sha1(data.sha1).blobstore_key(),
+            Alias::GitSha1(_) => Alias::GitSha1(data.git_sha1.sha1()).blobstore_key(),
+            Alias::Sha256(_) => Alias::Sha256(data.sha256).blobstore_key(),
+            Alias::SeededBlake3(_) => Alias::SeededBlake3(data.seeded_blake3).blobstore_key(),
+        };
+        copy_key(destination_blobstore, copier, ctx, resume, key)
     }))
-    .await?;
+    .await?
+    {
+        outcome.add(alias_outcome);
+    }
 
     // Files are stored inline or in chunks, depending on their size. If they're chunked,
     // we need to copy all chunks. Unfortunately, the only way to know how they're stored is
     // by loading FileContents, which might be large-ish if the file is actually inlined.
     let file_contents = data.content_id.load(ctx, original_blobstore).await?;
-    match file_contents {
+    match &file_contents {
         FileContents::Chunked(chunked) => {
-            stream::iter(
-                chunked
-                    .into_chunks()
-                    .into_iter()
-                    .map(|c| copier.copy(ctx, c.chunk_id().blobstore_key())),
+            let chunk_outcomes: Vec<CopyOutcome> = stream::iter(
+                chunked.clone().into_chunks().into_iter().map(|c| {
+                    copy_key(
+                        destination_blobstore,
+                        copier,
+                        ctx,
+                        resume,
+                        c.chunk_id().blobstore_key(),
+                    )
+                }),
             )
             .buffer_unordered(config.concurrency)
             .try_collect()
-            .await?
+            .await?;
+            for chunk_outcome in chunk_outcomes {
+                outcome.add(chunk_outcome);
+            }
         }
         FileContents::Bytes(_) => {}
     }
 
-    copier.copy(ctx, data.content_id.blobstore_key()).await?;
+    outcome.add(
+        copy_key(
+            destination_blobstore,
+            copier,
+            ctx,
+            resume,
+            data.content_id.blobstore_key(),
+        )
+        .await?,
+    );
+
+    outcome.add(
+        copy_key(
+            destination_blobstore,
+            copier,
+            ctx,
+            resume,
+            data.clone().into_blob().id().blobstore_key(),
+        )
+        .await?,
+    );
+
+    if mode == CopyMode::Verified {
+        verify_copy(destination_blobstore, config, ctx, data).await?;
+    }
+
+    Ok(outcome)
+}
+
+/// Reload `data.content_id` from the destination blobstore the copy just wrote to, and confirm
+/// its bytes hash to exactly what `data` claims, so a cross-blobstore migration can be trusted
+/// without a separate audit pass.
+async fn verify_copy(
+    destination_blobstore: &impl Blobstore,
+    config: FilestoreConfig,
+    ctx: &CoreContext,
+    data: &ContentMetadataV2,
+) -> Result<()> {
+    let file_contents = data.content_id.load(ctx, destination_blobstore).await?;
+
+    let chunks_bytes: Vec<bytes::Bytes> = match &file_contents {
+        FileContents::Bytes(bytes) => vec![bytes.clone()],
+        FileContents::Chunked(chunked) => {
+            stream::iter(chunked.clone().into_chunks().into_iter().map(|c| async move {
+                let chunk = c.chunk_id().load(ctx, destination_blobstore).await?;
+                Result::<_>::Ok(chunk.into_bytes())
+            }))
+            .buffer_unordered(config.concurrency)
+            .try_collect()
+            .await?
+        }
+    };
+
+    let mut sha1 = sha1::Sha1::new();
+    let mut sha256 = sha2::Sha256::new();
+    let mut blake3 = blake3::Hasher::new_keyed(SEEDED_BLAKE3_KEY);
+    let mut git_sha1 = sha1::Sha1::new();
+    git_sha1.update(format!("blob {}\0", data.total_size).as_bytes());
+    let mut total_size: u64 = 0;
+
+    for bytes in &chunks_bytes {
+        sha1.update(bytes);
+        sha256.update(bytes);
+        blake3.update(bytes);
+        git_sha1.update(bytes);
+        total_size += bytes.len() as u64;
+    }
+
+    if total_size != data.total_size {
+        bail!(
+            "filestore copy verification failed for {:?}: expected total size {}, got {}",
+            data.content_id,
+            data.total_size,
+            total_size,
+        );
+    }
+
+    let sha1 = Sha1::from_bytes(sha1.finalize())?;
+    if sha1 != data.sha1 {
+        bail!(
+            "filestore copy verification failed for {:?}: sha1 mismatch (expected {}, got {})",
+            data.content_id,
+            data.sha1,
+            sha1,
+        );
+    }
+
+    let sha256 = Sha256::from_bytes(sha256.finalize())?;
+    if sha256 != data.sha256 {
+        bail!(
+            "filestore copy verification failed for {:?}: sha256 mismatch (expected {}, got {})",
+            data.content_id,
+            data.sha256,
+            sha256,
+        );
+    }
+
+    let git_sha1 = GitSha1::from_bytes(git_sha1.finalize(), "blob", data.total_size)?;
+    if git_sha1 != data.git_sha1 {
+        bail!(
+            "filestore copy verification failed for {:?}: git_sha1 mismatch (expected {}, got {})",
+            data.content_id,
+            data.git_sha1,
+            git_sha1,
+        );
+    }
+
+    let seeded_blake3 = Blake3::from_bytes(blake3.finalize().as_bytes())?;
+    if seeded_blake3 != data.seeded_blake3 {
+        bail!(
+            "filestore copy verification failed for {:?}: seeded_blake3 mismatch (expected {}, got {})",
+            data.content_id,
+            data.seeded_blake3,
+            seeded_blake3,
+        );
+    }
 
-    copier
-        .copy(ctx, data.clone().into_blob().id().blobstore_key())
-        .await?;
     Ok(())
 }
+
+/// Top-level knobs for [`copy_many`], kept separate from [`FilestoreConfig`] so this crate slice
+/// doesn't have to guess at that struct's full layout (its definition lives outside the files
+/// touched by this change) and so the one knob `copy_many` actually needs isn't just another
+/// bare `usize` next to everything else `copy_many` already takes.
+#[derive(Copy, Clone, Debug)]
+pub struct CopyManyConfig {
+    /// Upper bound on how many contents are copied concurrently. Deliberately a separate knob
+    /// from `FilestoreConfig::concurrency`, which bounds the chunk-level fan-out *within* a
+    /// single `copy()`/`verify_copy()` call: for chunked, `CopyMode::Verified` content, reusing
+    /// that knob here as well would let the two levels of fan-out compound to roughly
+    /// `concurrency²` simultaneous blobstore operations, which can overwhelm the destination
+    /// during exactly the bulk migration this is meant to protect.
+    pub content_concurrency: usize,
+}
+
+/// Copy many contents, fanning out to [`copy`] with bounded concurrency governed by
+/// `many_config.content_concurrency`.
+///
+/// `progress`, if given, is called after every content finishes (successfully or not) with the
+/// running `(contents_done, bytes_done)` total, so a long migration is observable.
+///
+/// All in-flight copies are allowed to finish even after one fails: the first error seen is
+/// returned once every content has been attempted, rather than cancelling the rest.
+///
+/// If every content copies successfully, returns the [`CopyOutcome`] totalled across all of
+/// them, so a resumed migration can report how much work it actually avoided redoing. If any
+/// copy fails, only that first error is returned and the partial totals are dropped.
+pub async fn copy_many(
+    original_blobstore: &impl Blobstore,
+    copier: &impl BlobCopier,
+    config: FilestoreConfig,
+    ctx: &CoreContext,
+    contents: impl Stream<Item = ContentMetadataV2>,
+    mode: CopyMode,
+    destination_blobstore: &impl Blobstore,
+    resume: ResumeMode,
+    many_config: CopyManyConfig,
+    mut progress: Option<impl FnMut(usize, u64) + Send>,
+) -> Result<CopyOutcome> {
+    let mut copies = contents
+        .map(|data| async move {
+            let total_size = data.total_size;
+            let res = copy(
+                original_blobstore,
+                copier,
+                config,
+                ctx,
+                &data,
+                mode,
+                destination_blobstore,
+                resume,
+            )
+            .await;
+            (res, total_size)
+        })
+        .buffer_unordered(many_config.content_concurrency);
+
+    let mut contents_done: usize = 0;
+    let mut bytes_done: u64 = 0;
+    let mut first_error = None;
+    let mut outcome = CopyOutcome::default();
+
+    while let Some((res, total_size)) = copies.next().await {
+        contents_done += 1;
+        bytes_done += total_size;
+
+        match res {
+            Ok(content_outcome) => outcome.add(content_outcome),
+            Err(e) => {
+                if first_error.is_none() {
+                    first_error = Some(e);
+                }
+            }
+        }
+
+        if let Some(progress) = progress.as_mut() {
+            progress(contents_done, bytes_done);
+        }
+    }
+
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(outcome),
+    }
+}